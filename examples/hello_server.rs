@@ -19,7 +19,7 @@ impl win_service::ServiceHandler for HelloService {
 
         let listener = TcpListener::bind(":8080").map_err(|e| {
             error!("Failed to create TCP listener: {:?}", e);
-            ServiceError::Failed
+            ServiceError::Win32(e.raw_os_error().unwrap_or(1) as u32)
         })?;
 
         info!("successfully created TCP listener");
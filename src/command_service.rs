@@ -0,0 +1,267 @@
+//! A built-in [`ServiceHandler`] that wraps an arbitrary external command as
+//! a Windows service: spawns and supervises a child process, restarts it
+//! according to a configurable policy, and stops it gracefully (then
+//! forcefully, after a grace period) on `stop`/`shutdown`.
+
+use crate::{RemoteStatusHandle, ServiceError, ServiceHandler, StatusUpdater, SERVICE_USER_OWN_PROCESS};
+use core::ptr::null_mut;
+use log::{error, info, warn};
+use std::os::windows::process::CommandExt;
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use widestring::U16CString;
+use winapi::shared::winerror;
+use winapi::um::wincon;
+use winapi::um::winbase::CREATE_NEW_PROCESS_GROUP;
+use winapi::um::winnt::LPWSTR;
+use winapi::um::winsvc;
+
+/// Whether a wrapped command should be restarted after it exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Always,
+    OnFailure,
+    Never,
+}
+
+/// Configuration for [`CommandService`].
+#[derive(Clone)]
+pub struct CommandServiceConfig {
+    pub service_name: String,
+    pub program: String,
+    pub args: Vec<String>,
+    pub working_dir: Option<String>,
+    pub env: Vec<(String, String)>,
+    pub restart_policy: RestartPolicy,
+    pub restart_backoff: Duration,
+    pub stop_grace_period: Duration,
+}
+
+fn build_command(config: &CommandServiceConfig) -> Command {
+    let mut command = Command::new(&config.program);
+    command.args(&config.args);
+    if let Some(dir) = &config.working_dir {
+        command.current_dir(dir);
+    }
+    for (key, value) in &config.env {
+        command.env(key, value);
+    }
+    // Its own process group lets us send CTRL_BREAK_EVENT to just the
+    // wrapped command, without also signalling ourselves.
+    command.creation_flags(CREATE_NEW_PROCESS_GROUP);
+    command
+}
+
+/// A `ServiceHandler` whose real implementation is an external command
+/// (`config.program`), supervised for the lifetime of the service.
+pub struct CommandService {
+    config: CommandServiceConfig,
+    child: Arc<Mutex<Option<Child>>>,
+    supervisor: Option<thread::JoinHandle<()>>,
+}
+
+impl CommandService {
+    pub fn new(config: CommandServiceConfig) -> Self {
+        CommandService {
+            config,
+            child: Arc::new(Mutex::new(None)),
+            supervisor: None,
+        }
+    }
+
+    fn terminate(&mut self, updater: &mut StatusUpdater) {
+        let mut child_guard = self.child.lock().unwrap();
+        if let Some(child) = child_guard.as_mut() {
+            info!(
+                "sending CTRL_BREAK_EVENT to wrapped command (pid {})",
+                child.id()
+            );
+            let signaled = unsafe { wincon::GenerateConsoleCtrlEvent(wincon::CTRL_BREAK_EVENT, child.id()) };
+            if signaled == 0 {
+                // Services aren't normally console-attached, so this commonly
+                // fails; fall through to the grace-period wait and eventual
+                // kill rather than pretending the graceful signal went out.
+                warn!(
+                    "GenerateConsoleCtrlEvent failed ({:?}); falling back to the grace period before killing",
+                    std::io::Error::last_os_error()
+                );
+            }
+
+            let deadline = Instant::now() + self.config.stop_grace_period;
+            loop {
+                match child.try_wait() {
+                    Ok(Some(_)) => break,
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("error waiting on wrapped command: {:?}", e);
+                        break;
+                    }
+                }
+                if Instant::now() >= deadline {
+                    warn!("wrapped command did not exit within the grace period; killing it");
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    break;
+                }
+                updater.checkpoint_with_hint(deadline.saturating_duration_since(Instant::now()));
+                thread::sleep(Duration::from_millis(200));
+            }
+        }
+        // Taking the child tells `supervise` this exit was requested, not a
+        // crash, so it neither restarts nor reports a failure.
+        *child_guard = None;
+        drop(child_guard);
+
+        if let Some(supervisor) = self.supervisor.take() {
+            let _ = supervisor.join();
+        }
+    }
+}
+
+impl ServiceHandler for CommandService {
+    fn service_name(&self) -> &str {
+        &self.config.service_name
+    }
+
+    fn start(&mut self, updater: &mut StatusUpdater) -> Result<(), ServiceError> {
+        let child = build_command(&self.config).spawn().map_err(|e| {
+            error!(
+                "failed to spawn wrapped command '{}': {:?}",
+                self.config.program, e
+            );
+            ServiceError::Win32(e.raw_os_error().unwrap_or(winerror::ERROR_EXEC_FAILURE as i32) as u32)
+        })?;
+        *self.child.lock().unwrap() = Some(child);
+
+        let config = self.config.clone();
+        let child_handle = self.child.clone();
+        let remote_handle = updater.remote_handle();
+        self.supervisor = Some(thread::spawn(move || {
+            supervise(config, child_handle, remote_handle);
+        }));
+        Ok(())
+    }
+
+    fn stop(&mut self, updater: &mut StatusUpdater) {
+        self.terminate(updater);
+    }
+
+    fn shutdown(&mut self, updater: &mut StatusUpdater) {
+        self.terminate(updater);
+    }
+}
+
+// Waits on the wrapped command; either respawns it per
+// `config.restart_policy` or reports the final exit code to the SCM and
+// lets the process exit.
+fn supervise(
+    config: CommandServiceConfig,
+    child: Arc<Mutex<Option<Child>>>,
+    remote_handle: RemoteStatusHandle,
+) {
+    loop {
+        // Poll with `try_wait` rather than blocking on `wait` while holding
+        // the lock: a blocking `wait` would keep the guard held until the
+        // child exits, which is exactly what `terminate` needs in order to
+        // read `child.id()` and send it a graceful stop signal. Holding the
+        // lock across the wait would deadlock `stop`/`shutdown` against this
+        // thread for any child that doesn't exit on its own.
+        let exit_status = loop {
+            {
+                let mut guard = child.lock().unwrap();
+                match guard.as_mut() {
+                    Some(c) => match c.try_wait() {
+                        Ok(Some(status)) => break status,
+                        Ok(None) => {}
+                        Err(e) => {
+                            error!("failed to wait on wrapped command: {:?}", e);
+                            return;
+                        }
+                    },
+                    // stop()/shutdown() already took the child: we're done.
+                    None => return,
+                }
+            }
+            thread::sleep(Duration::from_millis(200));
+        };
+
+        // If stop()/shutdown() cleared the child while we were waiting, this
+        // exit was requested by us; don't restart or report a failure.
+        if child.lock().unwrap().is_none() {
+            return;
+        }
+
+        let should_restart = match config.restart_policy {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => !exit_status.success(),
+            RestartPolicy::Never => false,
+        };
+
+        if !should_restart {
+            let exit_code = exit_status.code().unwrap_or(1) as u32;
+            info!(
+                "wrapped command exited (code {}); not restarting",
+                exit_code
+            );
+            remote_handle.stop_with_exit_code(ServiceError::ServiceSpecific(exit_code));
+            std::process::exit(0);
+        }
+
+        warn!(
+            "wrapped command exited unexpectedly; restarting in {:?}",
+            config.restart_backoff
+        );
+        thread::sleep(config.restart_backoff);
+
+        match build_command(&config).spawn() {
+            Ok(new_child) => {
+                *child.lock().unwrap() = Some(new_child);
+            }
+            Err(e) => {
+                error!("failed to respawn wrapped command: {:?}", e);
+                remote_handle.stop_with_exit_code(ServiceError::Win32(winerror::ERROR_EXEC_FAILURE));
+                std::process::exit(0);
+            }
+        }
+    }
+}
+
+static COMMAND_SERVICE_CONFIG: Mutex<Option<CommandServiceConfig>> = Mutex::new(None);
+
+unsafe extern "system" fn command_service_proc(_num_service_args: u32, _service_args: *mut LPWSTR) {
+    let config = COMMAND_SERVICE_CONFIG
+        .lock()
+        .unwrap()
+        .take()
+        .expect("command_service_main must set the config before dispatch");
+    let mut service = CommandService::new(config);
+    unsafe {
+        crate::run_service_main(&mut service, SERVICE_USER_OWN_PROCESS);
+    }
+}
+
+/// Runs `config.program` as the Windows service `config.service_name`,
+/// wrapped in a [`CommandService`]. This is the entry point behind
+/// `single_service_main`'s `--wrap` option.
+pub fn command_service_main(config: CommandServiceConfig) {
+    let service_name_wstr = U16CString::from_str(&config.service_name).unwrap();
+    *COMMAND_SERVICE_CONFIG.lock().unwrap() = Some(config);
+
+    unsafe {
+        let service_table = [
+            winsvc::SERVICE_TABLE_ENTRYW {
+                lpServiceName: service_name_wstr.as_ptr(),
+                lpServiceProc: Some(command_service_proc),
+            },
+            winsvc::SERVICE_TABLE_ENTRYW {
+                lpServiceName: null_mut(),
+                lpServiceProc: None,
+            },
+        ];
+        if winsvc::StartServiceCtrlDispatcherW(&service_table[0]) == 0 {
+            error!("StartServiceCtrlDispatcherW failed");
+        }
+    }
+}
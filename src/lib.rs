@@ -8,8 +8,10 @@
 use core::ptr::null_mut;
 use core::u32;
 use log::{error, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::{Condvar, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
 use std::time::Duration;
 use widestring::{U16CStr, U16CString};
 use winapi::shared::guiddef::GUID;
@@ -20,6 +22,11 @@ use winapi::um::winuser::{PBT_POWERSETTINGCHANGE, POWERBROADCAST_SETTING};
 
 pub extern crate widestring;
 
+mod command_service;
+mod manager;
+pub use command_service::{CommandService, CommandServiceConfig, RestartPolicy};
+pub use manager::{ManagerError, ServiceAccount, ServiceConfig, ServiceManager, ServiceStartType, ServiceStatusInfo};
+
 // this is missing from winapi
 const SERVICE_USER_OWN_PROCESS: u32 = 0x50;
 
@@ -40,23 +47,63 @@ pub trait ServiceHandler {
 
     fn param_change(&mut self) {}
     fn power_setting(&mut self, power_setting: &GUID, data: &[u8]) {}
+
+    /// Called for `SERVICE_CONTROL_SESSIONCHANGE`, with the `WTS_*` reason
+    /// code (`event_type`) and the affected terminal session id.
+    fn session_change(&mut self, reason: u32, session_id: u32) {}
 }
 
-#[derive(Debug)]
+/// The exit code a service reports to the SCM when it stops: because `start`
+/// failed, because `stop`/`shutdown` observed a failure and called
+/// [`StatusUpdater::set_exit_code`], or because a background thread called
+/// [`RemoteStatusHandle::stop_with_exit_code`].
+#[derive(Debug, Clone, Copy)]
 pub enum ServiceError {
-    Failed,
+    /// A standard Win32 error code, reported as `dwWin32ExitCode`.
+    Win32(u32),
+    /// A service-specific error code, reported as `dwServiceSpecificExitCode`
+    /// (with `dwWin32ExitCode` set to `ERROR_SERVICE_SPECIFIC_ERROR`).
+    ServiceSpecific(u32),
+}
+
+impl ServiceError {
+    // The (dwWin32ExitCode, dwServiceSpecificExitCode) pair SERVICE_STATUS expects.
+    fn to_status_fields(self) -> (u32, u32) {
+        match self {
+            ServiceError::Win32(code) => (code, 0),
+            ServiceError::ServiceSpecific(code) => (winerror::ERROR_SERVICE_SPECIFIC_ERROR, code),
+        }
+    }
+}
+
+// SERVICE_STATUS's dwWaitHint is a millisecond count that has to fit in a
+// u32; clamp down to that range instead of overflowing into it.
+fn wait_hint_millis(wait_hint: Duration) -> u32 {
+    wait_hint.as_millis().min(u32::MAX as u128) as u32
 }
 
 struct ServiceStatusHandle {
     handle: winsvc::SERVICE_STATUS_HANDLE,
 }
 
+// The state shared between `begin_pending` and the background ticker thread
+// it spawns; `report_progress` updates `done`, and the ticker derives the
+// `dwWaitHint` from `total - done` on every tick.
+struct PendingProgress {
+    total: Duration,
+    done: Mutex<Duration>,
+    stop: AtomicBool,
+}
+
 pub struct StatusUpdater {
     service_status_handle: winsvc::SERVICE_STATUS_HANDLE,
     checkpoint: u32,
     current_state: u32,
     service_type: u32,
     controls_accepted: u32,
+    exit_code: Option<ServiceError>,
+    preshutdown_wait_hint: Duration,
+    pending_progress: Option<Arc<PendingProgress>>,
 }
 
 impl StatusUpdater {
@@ -65,19 +112,30 @@ impl StatusUpdater {
         self.checkpoint += 1;
     }
 
+    /// Records the error a handler wants reported to the SCM the next time
+    /// this service transitions to `SERVICE_STOPPED`. Intended for use from
+    /// `stop()`/`shutdown()`, which return no `Result` of their own.
+    pub fn set_exit_code(&mut self, exit_code: ServiceError) {
+        self.exit_code = Some(exit_code);
+    }
+
     fn send_update(&mut self, wait_hint: Duration) {
         if self.service_status_handle.is_null() {
             return;
         }
 
+        let (win32_exit_code, service_specific_exit_code) = self
+            .exit_code
+            .map_or((0, 0), ServiceError::to_status_fields);
+
         let mut status = winsvc::SERVICE_STATUS {
             dwCheckPoint: self.checkpoint,
             dwControlsAccepted: self.controls_accepted,
             dwCurrentState: self.current_state,
-            dwWaitHint: wait_hint.as_millis().max(u32::MAX.into()) as u32,
-            dwServiceSpecificExitCode: 0,
+            dwWaitHint: wait_hint_millis(wait_hint),
+            dwServiceSpecificExitCode: service_specific_exit_code,
             dwServiceType: self.service_type,
-            dwWin32ExitCode: 0,
+            dwWin32ExitCode: win32_exit_code,
         };
 
         unsafe {
@@ -113,6 +171,17 @@ impl StatusUpdater {
         self.set_accept_bits(winsvc::SERVICE_ACCEPT_SHUTDOWN, value);
     }
 
+    pub fn accepts_preshutdown(&mut self, value: bool) {
+        self.set_accept_bits(winsvc::SERVICE_ACCEPT_PRESHUTDOWN, value);
+    }
+
+    /// How long `SERVICE_CONTROL_PRESHUTDOWN` is allowed to run before the
+    /// SCM considers the service hung. Defaults to 3 minutes, matching the
+    /// SCM's own default.
+    pub fn set_preshutdown_wait_hint(&mut self, wait_hint: Duration) {
+        self.preshutdown_wait_hint = wait_hint;
+    }
+
     pub fn accepts_param_change(&mut self, value: bool) {
         self.set_accept_bits(winsvc::SERVICE_ACCEPT_PARAMCHANGE, value);
     }
@@ -124,6 +193,125 @@ impl StatusUpdater {
     pub fn accepts_power_event(&mut self, value: bool) {
         self.set_accept_bits(winsvc::SERVICE_ACCEPT_POWEREVENT, value);
     }
+
+    /// A `Send` handle that lets a background thread (e.g. a supervised
+    /// child process monitor) report this service's final exit code and
+    /// move it to `SERVICE_STOPPED` from outside the control handler.
+    pub fn remote_handle(&self) -> RemoteStatusHandle {
+        RemoteStatusHandle {
+            service_status_handle: self.service_status_handle as usize,
+            service_type: self.service_type,
+            controls_accepted: self.controls_accepted,
+        }
+    }
+
+    /// Moves this service into `state` (normally `SERVICE_START_PENDING` or
+    /// `SERVICE_STOP_PENDING`) and starts a background thread that keeps
+    /// emitting incrementing `dwCheckPoint` updates with a `dwWaitHint` of
+    /// `total` minus the progress last passed to `report_progress`, so a slow
+    /// transition isn't killed as hung. The hint does not shrink on its own
+    /// as time passes: call `report_progress` as work completes, and
+    /// `end_pending` once the transition is done.
+    pub fn begin_pending(&mut self, state: u32, total: Duration) {
+        self.end_pending();
+        self.set_state(state);
+        self.checkpoint_with_hint(total);
+
+        let progress = Arc::new(PendingProgress {
+            total,
+            done: Mutex::new(Duration::from_secs(0)),
+            stop: AtomicBool::new(false),
+        });
+        let ticker_progress = progress.clone();
+        let remote_handle = self.remote_handle();
+        thread::spawn(move || {
+            let mut checkpoint: u32 = 1;
+            loop {
+                thread::sleep(Duration::from_millis(500));
+                if ticker_progress.stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let done = *ticker_progress.done.lock().unwrap();
+                let remaining = ticker_progress.total.saturating_sub(done);
+                remote_handle.checkpoint_update(state, checkpoint, remaining);
+                checkpoint += 1;
+            }
+        });
+        self.pending_progress = Some(progress);
+    }
+
+    /// Reports how much of the `total` passed to `begin_pending` has
+    /// elapsed, shrinking the `dwWaitHint` the background ticker reports.
+    pub fn report_progress(&mut self, done: Duration) {
+        if let Some(progress) = &self.pending_progress {
+            *progress.done.lock().unwrap() = done;
+        }
+    }
+
+    /// Stops the background ticker started by `begin_pending`, if any.
+    pub fn end_pending(&mut self) {
+        if let Some(progress) = self.pending_progress.take() {
+            progress.stop.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// See [`StatusUpdater::remote_handle`].
+#[derive(Clone, Copy)]
+pub struct RemoteStatusHandle {
+    service_status_handle: usize,
+    service_type: u32,
+    controls_accepted: u32,
+}
+
+unsafe impl Send for RemoteStatusHandle {}
+
+impl RemoteStatusHandle {
+    pub fn stop_with_exit_code(&self, exit_code: ServiceError) {
+        if self.service_status_handle == 0 {
+            return;
+        }
+        let (win32_exit_code, service_specific_exit_code) = exit_code.to_status_fields();
+        let mut status = winsvc::SERVICE_STATUS {
+            dwServiceType: self.service_type,
+            dwCurrentState: winsvc::SERVICE_STOPPED,
+            dwControlsAccepted: 0,
+            dwWin32ExitCode: win32_exit_code,
+            dwServiceSpecificExitCode: service_specific_exit_code,
+            dwCheckPoint: 0,
+            dwWaitHint: 0,
+        };
+        unsafe {
+            winsvc::SetServiceStatus(
+                self.service_status_handle as winsvc::SERVICE_STATUS_HANDLE,
+                &mut status,
+            );
+        }
+    }
+
+    // Used by `begin_pending`'s background ticker, which runs on a thread
+    // that has no access to the `StatusUpdater` behind the control handler's
+    // mutex.
+    fn checkpoint_update(&self, state: u32, checkpoint: u32, wait_hint: Duration) {
+        if self.service_status_handle == 0 {
+            return;
+        }
+        let mut status = winsvc::SERVICE_STATUS {
+            dwServiceType: self.service_type,
+            dwCurrentState: state,
+            dwControlsAccepted: self.controls_accepted,
+            dwWin32ExitCode: 0,
+            dwServiceSpecificExitCode: 0,
+            dwCheckPoint: checkpoint,
+            dwWaitHint: wait_hint_millis(wait_hint),
+        };
+        unsafe {
+            winsvc::SetServiceStatus(
+                self.service_status_handle as winsvc::SERVICE_STATUS_HANDLE,
+                &mut status,
+            );
+        }
+    }
 }
 
 pub enum ServiceControl {
@@ -236,6 +424,47 @@ unsafe extern "system" fn service_control_handler(
                 }
             }
         }
+        winsvc::SERVICE_CONTROL_SHUTDOWN => {
+            // Like SERVICE_CONTROL_STOP, the SCM never sends another control
+            // request after this one, so finish the same way: finalize the
+            // state to SERVICE_STOPPED and wake run_service_main's wait loop.
+            info!("Received SERVICE_CONTROL_SHUTDOWN");
+            state.handler.shutdown(status_updater);
+            info!("Service state is SERVICE_STOPPED");
+            status_updater.set_state(winsvc::SERVICE_STOPPED);
+            status_updater.checkpoint();
+            drop(state);
+            context.condvar.notify_all();
+        }
+        winsvc::SERVICE_CONTROL_PRESHUTDOWN => {
+            info!("Received SERVICE_CONTROL_PRESHUTDOWN");
+            // begin_pending's background ticker keeps checking in with the
+            // SCM while preshutdown() runs on this thread, so a slow cleanup
+            // isn't treated as hung, the way the Ceph service runner does.
+            status_updater.begin_pending(winsvc::SERVICE_STOP_PENDING, status_updater.preshutdown_wait_hint);
+
+            info!("Calling preshutdown() function");
+            state.handler.preshutdown(status_updater);
+            info!("preshutdown() returned");
+
+            status_updater.end_pending();
+
+            // Like SERVICE_CONTROL_STOP, finalize to SERVICE_STOPPED and wake
+            // run_service_main's wait loop instead of leaving the SCM seeing
+            // a stale SERVICE_STOP_PENDING forever.
+            info!("Service state is SERVICE_STOPPED");
+            status_updater.set_state(winsvc::SERVICE_STOPPED);
+            status_updater.checkpoint();
+            drop(state);
+            context.condvar.notify_all();
+        }
+        winsvc::SERVICE_CONTROL_SESSIONCHANGE => {
+            info!("Received SERVICE_CONTROL_SESSIONCHANGE");
+            let notification = event_data as *const winapi::um::winuser::WTSSESSION_NOTIFICATION;
+            state
+                .handler
+                .session_change(event_type, (*notification).dwSessionId);
+        }
         unrecognized_control => {
             info!(
                 "Received unrecognized service control ({:#x})",
@@ -253,8 +482,17 @@ unsafe extern "system" fn service_proc<S: ServiceHandler + Default>(
     service_args: *mut LPWSTR,
 ) {
     let mut service_impl: S = S::default();
-    let service_handler = &mut service_impl;
+    unsafe {
+        run_service_main(&mut service_impl, SERVICE_USER_OWN_PROCESS);
+    }
+}
 
+// The common body behind every `lpServiceProc` trampoline: registers the
+// control handler, runs `handler.start()`, then blocks until the service
+// transitions to `SERVICE_STOPPED`. Shared by `service_proc` (one service
+// per process) and `multi_service_proc` (several services sharing a
+// process, see `multi_service_main`).
+unsafe fn run_service_main(service_handler: &mut dyn ServiceHandler, service_type: u32) {
     let service_name = service_handler.service_name();
     info!("service_main starting for: {}", service_name);
 
@@ -267,8 +505,11 @@ unsafe extern "system" fn service_proc<S: ServiceHandler + Default>(
                     controls_accepted: winsvc::SERVICE_ACCEPT_STOP,
                     checkpoint: 0,
                     service_status_handle: null_mut(),
-                    service_type: SERVICE_USER_OWN_PROCESS,
+                    service_type,
                     current_state: winsvc::SERVICE_START_PENDING,
+                    exit_code: None,
+                    preshutdown_wait_hint: Duration::from_secs(180),
+                    pending_progress: None,
                 },
                 handler: service_handler,
             }),
@@ -293,19 +534,22 @@ unsafe extern "system" fn service_proc<S: ServiceHandler + Default>(
 
             info!("sending status update for START_PENDING");
             let status_updater = &mut state.status_updater;
-            // status_updater.checkpoint();
+            status_updater.begin_pending(winsvc::SERVICE_START_PENDING, Duration::from_secs(30));
 
             // <-- state is SERVICE_START_PENDING
             // Call into the service code to start it.
             match state.handler.start(status_updater) {
                 Err(e) => {
                     error!("service failed to start: {:?}", e);
+                    status_updater.end_pending();
+                    status_updater.set_exit_code(e);
                     status_updater.set_state(winsvc::SERVICE_STOPPED);
                     status_updater.checkpoint();
                     return;
                 }
                 Ok(()) => {}
             }
+            status_updater.end_pending();
 
             info!("Sending SERVICE_RUNNING");
             status_updater.set_state(winsvc::SERVICE_RUNNING);
@@ -329,6 +573,166 @@ unsafe extern "system" fn service_proc<S: ServiceHandler + Default>(
     }
 }
 
+// The maximum number of services `multi_service_main` can host in a single
+// process. `lpServiceProc` is a bare `extern "system" fn`, so there is no
+// way to close over which entry it belongs to; instead we generate this
+// many distinct trampolines ahead of time and hand one to each entry, with
+// `MULTI_SERVICE_ENTRIES` recording, at the index of each trampoline's slot
+// (the entry's position in the table passed to `multi_service_main`), which
+// entry that trampoline should dispatch to.
+const MAX_MULTI_SERVICES: usize = 32;
+
+struct MultiServiceEntry {
+    name: String,
+    creator: fn() -> Box<dyn ServiceHandler>,
+}
+
+static MULTI_SERVICE_ENTRIES: Mutex<Vec<MultiServiceEntry>> = Mutex::new(Vec::new());
+
+macro_rules! multi_service_trampoline {
+    ($fn_name:ident, $slot:expr) => {
+        unsafe extern "system" fn $fn_name(num_service_args: u32, service_args: *mut LPWSTR) {
+            unsafe {
+                multi_service_proc($slot, num_service_args, service_args);
+            }
+        }
+    };
+}
+
+multi_service_trampoline!(multi_service_trampoline_0, 0);
+multi_service_trampoline!(multi_service_trampoline_1, 1);
+multi_service_trampoline!(multi_service_trampoline_2, 2);
+multi_service_trampoline!(multi_service_trampoline_3, 3);
+multi_service_trampoline!(multi_service_trampoline_4, 4);
+multi_service_trampoline!(multi_service_trampoline_5, 5);
+multi_service_trampoline!(multi_service_trampoline_6, 6);
+multi_service_trampoline!(multi_service_trampoline_7, 7);
+multi_service_trampoline!(multi_service_trampoline_8, 8);
+multi_service_trampoline!(multi_service_trampoline_9, 9);
+multi_service_trampoline!(multi_service_trampoline_10, 10);
+multi_service_trampoline!(multi_service_trampoline_11, 11);
+multi_service_trampoline!(multi_service_trampoline_12, 12);
+multi_service_trampoline!(multi_service_trampoline_13, 13);
+multi_service_trampoline!(multi_service_trampoline_14, 14);
+multi_service_trampoline!(multi_service_trampoline_15, 15);
+multi_service_trampoline!(multi_service_trampoline_16, 16);
+multi_service_trampoline!(multi_service_trampoline_17, 17);
+multi_service_trampoline!(multi_service_trampoline_18, 18);
+multi_service_trampoline!(multi_service_trampoline_19, 19);
+multi_service_trampoline!(multi_service_trampoline_20, 20);
+multi_service_trampoline!(multi_service_trampoline_21, 21);
+multi_service_trampoline!(multi_service_trampoline_22, 22);
+multi_service_trampoline!(multi_service_trampoline_23, 23);
+multi_service_trampoline!(multi_service_trampoline_24, 24);
+multi_service_trampoline!(multi_service_trampoline_25, 25);
+multi_service_trampoline!(multi_service_trampoline_26, 26);
+multi_service_trampoline!(multi_service_trampoline_27, 27);
+multi_service_trampoline!(multi_service_trampoline_28, 28);
+multi_service_trampoline!(multi_service_trampoline_29, 29);
+multi_service_trampoline!(multi_service_trampoline_30, 30);
+multi_service_trampoline!(multi_service_trampoline_31, 31);
+
+type ServiceProc = unsafe extern "system" fn(u32, *mut LPWSTR);
+
+static MULTI_SERVICE_TRAMPOLINES: [ServiceProc; MAX_MULTI_SERVICES] = [
+    multi_service_trampoline_0,
+    multi_service_trampoline_1,
+    multi_service_trampoline_2,
+    multi_service_trampoline_3,
+    multi_service_trampoline_4,
+    multi_service_trampoline_5,
+    multi_service_trampoline_6,
+    multi_service_trampoline_7,
+    multi_service_trampoline_8,
+    multi_service_trampoline_9,
+    multi_service_trampoline_10,
+    multi_service_trampoline_11,
+    multi_service_trampoline_12,
+    multi_service_trampoline_13,
+    multi_service_trampoline_14,
+    multi_service_trampoline_15,
+    multi_service_trampoline_16,
+    multi_service_trampoline_17,
+    multi_service_trampoline_18,
+    multi_service_trampoline_19,
+    multi_service_trampoline_20,
+    multi_service_trampoline_21,
+    multi_service_trampoline_22,
+    multi_service_trampoline_23,
+    multi_service_trampoline_24,
+    multi_service_trampoline_25,
+    multi_service_trampoline_26,
+    multi_service_trampoline_27,
+    multi_service_trampoline_28,
+    multi_service_trampoline_29,
+    multi_service_trampoline_30,
+    multi_service_trampoline_31,
+];
+
+unsafe extern "system" fn multi_service_proc(
+    slot: usize,
+    num_service_args: u32,
+    service_args: *mut LPWSTR,
+) {
+    let creator = {
+        let entries = MULTI_SERVICE_ENTRIES.lock().unwrap();
+        info!("multi_service_proc: dispatching slot {} ('{}')", slot, entries[slot].name);
+        entries[slot].creator
+    };
+    let mut handler = creator();
+    unsafe {
+        run_service_main(&mut *handler, winsvc::SERVICE_WIN32_SHARE_PROCESS);
+    }
+}
+
+/// Hosts several named services in one process, the way `SERVICE_WIN32_SHARE_PROCESS`
+/// services are normally run. Each entry's `creator` is invoked only once the
+/// SCM starts that particular service.
+pub fn multi_service_main(entries: &[ServiceEntry]) {
+    assert!(
+        entries.len() <= MAX_MULTI_SERVICES,
+        "multi_service_main supports at most {} services, got {}",
+        MAX_MULTI_SERVICES,
+        entries.len()
+    );
+
+    {
+        let mut stored_entries = MULTI_SERVICE_ENTRIES.lock().unwrap();
+        stored_entries.clear();
+        stored_entries.extend(entries.iter().map(|entry| MultiServiceEntry {
+            name: entry.name.to_string(),
+            creator: entry.creator,
+        }));
+    }
+
+    let name_wstrs: Vec<U16CString> = entries
+        .iter()
+        .map(|entry| U16CString::from_str(entry.name).unwrap())
+        .collect();
+
+    let mut service_table: Vec<winsvc::SERVICE_TABLE_ENTRYW> = name_wstrs
+        .iter()
+        .zip(MULTI_SERVICE_TRAMPOLINES.iter())
+        .map(|(name_wstr, trampoline)| winsvc::SERVICE_TABLE_ENTRYW {
+            lpServiceName: name_wstr.as_ptr(),
+            lpServiceProc: Some(*trampoline),
+        })
+        .collect();
+    service_table.push(winsvc::SERVICE_TABLE_ENTRYW {
+        lpServiceName: null_mut(),
+        lpServiceProc: None,
+    });
+
+    info!("Calling StartServiceCtrlDispatcherW for {} services", entries.len());
+    unsafe {
+        if winsvc::StartServiceCtrlDispatcherW(service_table.as_ptr()) != 0 {
+            // succeeded
+        } else {
+            error!("StartServiceCtrlDispatcherW failed");
+        }
+    }
+}
+
 mod standalone {
     use super::*;
 
@@ -353,6 +757,9 @@ mod standalone {
             current_state: 0,
             service_status_handle: null_mut(),
             service_type: 0,
+            exit_code: None,
+            preshutdown_wait_hint: Duration::from_secs(180),
+            pending_progress: None,
         };
 
         eprintln!("Running service in standalone mode.");
@@ -402,6 +809,89 @@ mod standalone {
     }
 }
 
+// Implements the `install`/`uninstall`/`start`/`stop`/`query` subcommands
+// exposed by `single_service_main`, letting the service binary manage its
+// own SCM registration.
+fn run_management_command(command: &str, service_name: &str, matches: &getopts::Matches) {
+    let manager = match ServiceManager::local_computer() {
+        Ok(manager) => manager,
+        Err(e) => {
+            eprintln!("Failed to open the Service Control Manager: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match command {
+        "install" => {
+            let binary_path = std::env::current_exe()
+                .expect("failed to get path of the current executable")
+                .to_string_lossy()
+                .into_owned();
+            let start_type = match matches.opt_str("start-type").as_deref() {
+                None | Some("demand") => ServiceStartType::DemandStart,
+                Some("auto") => ServiceStartType::AutoStart,
+                Some("disabled") => ServiceStartType::Disabled,
+                Some(other) => {
+                    eprintln!("Unrecognized --start-type '{}'", other);
+                    std::process::exit(1);
+                }
+            };
+            let dependency_strings = matches.opt_strs("depend");
+            let dependencies: Vec<&str> =
+                dependency_strings.iter().map(String::as_str).collect();
+            let account_username = matches.opt_str("account");
+            let account_password = matches.opt_str("password");
+            let account = account_username.as_deref().map(|username| ServiceAccount {
+                username,
+                password: account_password.as_deref(),
+            });
+            // Bake --wrap/--restart (and the wrapped command's own free
+            // arguments) into the installed service's command line, so the
+            // service started by the SCM runs the same CommandService the
+            // caller would get by invoking this binary with --wrap directly.
+            let mut extra_args: Vec<String> = Vec::new();
+            if let Some(program) = matches.opt_str("wrap") {
+                extra_args.push("--wrap".to_string());
+                extra_args.push(program);
+                if let Some(restart) = matches.opt_str("restart") {
+                    extra_args.push("--restart".to_string());
+                    extra_args.push(restart);
+                }
+                extra_args.extend(matches.free.iter().skip(1).cloned());
+            }
+            let extra_args: Vec<&str> = extra_args.iter().map(String::as_str).collect();
+            manager.install_service(&ServiceConfig {
+                name: service_name,
+                display_name: matches.opt_str("display-name").as_deref().unwrap_or(service_name),
+                description: matches.opt_str("description").as_deref(),
+                binary_path: &binary_path,
+                args: &extra_args,
+                start_type,
+                dependencies: &dependencies,
+                account,
+            })
+        }
+        "uninstall" => manager.delete_service(service_name),
+        "start" => manager.start_service(service_name),
+        "stop" => manager.stop_service(service_name).map(|_| ()),
+        "query" => manager.query_service(service_name).map(|status| {
+            println!(
+                "state={} pid={} checkpoint={} wait_hint={:?}",
+                status.state, status.process_id, status.checkpoint, status.wait_hint
+            );
+        }),
+        other => {
+            eprintln!("Unrecognized command '{}'", other);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("'{}' failed: {:?}", command, e);
+        std::process::exit(1);
+    }
+}
+
 pub fn single_service_main<S: ServiceHandler + Send + Default + 'static>(service_name: &str) {
     let program_name: &str = "<program.exe>";
 
@@ -414,12 +904,67 @@ pub fn single_service_main<S: ServiceHandler + Send + Default + 'static>(service
         "Run a specific service as a standalone process, not under the Service Control Manager.",
         "SERVICE",
     );
+    opts.optopt(
+        "",
+        "display-name",
+        "Display name to use with 'install' (defaults to the service name).",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "description",
+        "Description to use with 'install'.",
+        "TEXT",
+    );
+    opts.optopt(
+        "",
+        "start-type",
+        "Start type to use with 'install': auto, demand, or disabled (default: demand).",
+        "TYPE",
+    );
+    opts.optmulti(
+        "",
+        "depend",
+        "A service this service depends on; may be repeated. Used with 'install'.",
+        "SERVICE",
+    );
+    opts.optopt(
+        "",
+        "account",
+        "Account to run the service as, used with 'install' (defaults to LocalSystem).",
+        "ACCOUNT",
+    );
+    opts.optopt(
+        "",
+        "password",
+        "Password for --account, used with 'install'.",
+        "PASSWORD",
+    );
+    opts.optopt(
+        "w",
+        "wrap",
+        "Run PROGRAM (and any remaining free arguments, as its own arguments) as this \
+         service's implementation, instead of running S.",
+        "PROGRAM",
+    );
+    opts.optopt(
+        "",
+        "restart",
+        "Restart policy for --wrap's child process: always, on-failure, or never \
+         (default: on-failure).",
+        "POLICY",
+    );
     opts.optflag("h", "help", "Show detailed help");
 
+    let usage = format!(
+        "{} [install|uninstall|start|stop|query] [options]",
+        program_name
+    );
+
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => {
             if m.opt_present("help") {
-                print!("{}", opts.usage(program_name));
+                print!("{}", opts.usage(&usage));
                 std::process::exit(1);
             }
             if let Some(s) = m.opt_str("standalone") {
@@ -427,15 +972,45 @@ pub fn single_service_main<S: ServiceHandler + Send + Default + 'static>(service
                 standalone::run_service_standalone::<S>(service_name);
                 std::process::exit(1);
             }
-            if !m.free.is_empty() {
-                eprintln!("Unexpected args: {:?}", m.free);
-                opts.short_usage(program_name);
-                std::process::exit(1);
+            // A management subcommand (install/uninstall/start/stop/query)
+            // takes priority over --wrap: `--wrap PROGRAM install` installs
+            // a service that runs PROGRAM, it doesn't run PROGRAM itself.
+            // Under --wrap, any other free arguments are PROGRAM's own.
+            let is_management_command = matches!(
+                m.free.get(0).map(String::as_str),
+                Some("install") | Some("uninstall") | Some("start") | Some("stop") | Some("query")
+            );
+            if is_management_command || (!m.free.is_empty() && !m.opt_present("wrap")) {
+                run_management_command(&m.free[0], service_name, &m);
+                std::process::exit(0);
+            }
+            if let Some(program) = m.opt_str("wrap") {
+                let restart_policy = match m.opt_str("restart").as_deref() {
+                    None | Some("on-failure") => RestartPolicy::OnFailure,
+                    Some("always") => RestartPolicy::Always,
+                    Some("never") => RestartPolicy::Never,
+                    Some(other) => {
+                        eprintln!("Unrecognized --restart '{}'", other);
+                        std::process::exit(1);
+                    }
+                };
+                command_service::command_service_main(CommandServiceConfig {
+                    service_name: service_name.to_string(),
+                    program,
+                    args: m.free.clone(),
+                    working_dir: None,
+                    env: Vec::new(),
+                    restart_policy,
+                    restart_backoff: Duration::from_secs(5),
+                    stop_grace_period: Duration::from_secs(10),
+                });
+                std::process::exit(0);
             }
+            m
         }
         Err(e) => {
             eprintln!("{:?}", e);
-            print!("{}", opts.short_usage(program_name));
+            print!("{}", opts.short_usage(&usage));
             std::process::exit(1);
         }
     };
@@ -0,0 +1,345 @@
+//! A thin wrapper around the Service Control Manager (SCM) APIs
+//! (`OpenSCManagerW`, `CreateServiceW`, `OpenServiceW`, `DeleteService`,
+//! `ControlService`, `QueryServiceStatusEx`, `ChangeServiceConfig2W`) so a
+//! `ServiceHandler` binary can install, remove, start, stop, and query
+//! itself.
+
+use core::ptr::null_mut;
+use std::time::Duration;
+use widestring::U16CString;
+use winapi::shared::minwindef::DWORD;
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::winsvc;
+
+/// An error returned by a [`ServiceManager`] operation: the `GetLastError()`
+/// value captured immediately after the failing Win32 call.
+#[derive(Debug)]
+pub struct ManagerError(pub u32);
+
+impl ManagerError {
+    fn last() -> Self {
+        ManagerError(unsafe { GetLastError() })
+    }
+}
+
+/// The service start type, as passed to `CreateServiceW`'s `dwStartType`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceStartType {
+    Boot,
+    System,
+    AutoStart,
+    DemandStart,
+    Disabled,
+}
+
+impl ServiceStartType {
+    fn to_win32(self) -> DWORD {
+        match self {
+            ServiceStartType::Boot => winsvc::SERVICE_BOOT_START,
+            ServiceStartType::System => winsvc::SERVICE_SYSTEM_START,
+            ServiceStartType::AutoStart => winsvc::SERVICE_AUTO_START,
+            ServiceStartType::DemandStart => winsvc::SERVICE_DEMAND_START,
+            ServiceStartType::Disabled => winsvc::SERVICE_DISABLED,
+        }
+    }
+}
+
+/// The account a service should run as. `LocalSystem` is used when this is
+/// omitted from [`ServiceConfig`].
+pub struct ServiceAccount<'a> {
+    pub username: &'a str,
+    pub password: Option<&'a str>,
+}
+
+/// Describes a service to be installed with [`ServiceManager::install_service`].
+pub struct ServiceConfig<'a> {
+    pub name: &'a str,
+    pub display_name: &'a str,
+    pub description: Option<&'a str>,
+    /// Path to the service's executable. Quoted automatically when building
+    /// `lpBinaryPathName`, since `CreateServiceW` requires quoting whenever
+    /// the path contains a space (e.g. `C:\Program Files\...`).
+    pub binary_path: &'a str,
+    /// Extra arguments to bake into `lpBinaryPathName` after `binary_path`
+    /// (e.g. `--wrap`/`--restart` for an installed `CommandService`). Each
+    /// argument is quoted individually.
+    pub args: &'a [&'a str],
+    pub start_type: ServiceStartType,
+    pub dependencies: &'a [&'a str],
+    pub account: Option<ServiceAccount<'a>>,
+}
+
+/// A snapshot of `SERVICE_STATUS_PROCESS`, as returned by
+/// [`ServiceManager::query_service`].
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceStatusInfo {
+    pub state: DWORD,
+    pub process_id: DWORD,
+    pub checkpoint: DWORD,
+    pub wait_hint: Duration,
+}
+
+/// A handle to the Service Control Manager database, opened with
+/// [`ServiceManager::local_computer`].
+pub struct ServiceManager {
+    handle: winsvc::SC_HANDLE,
+}
+
+// Builds a NUL-separated, double-NUL-terminated wide string, the format
+// `CreateServiceW` expects for `lpDependencies`. Returns `None` if there are
+// no dependencies, matching the "no dependencies" convention of passing a
+// null pointer.
+fn build_dependencies_wstr(dependencies: &[&str]) -> Option<Vec<u16>> {
+    if dependencies.is_empty() {
+        return None;
+    }
+    let mut buf: Vec<u16> = Vec::new();
+    for dep in dependencies {
+        buf.extend(U16CString::from_str(dep).unwrap().as_slice());
+        buf.push(0);
+    }
+    buf.push(0);
+    Some(buf)
+}
+
+// Quotes a single command-line argument following the same backslash/quote
+// escaping rules as `CommandLineToArgvW` (the counterpart `main`'s argv
+// parsing uses), so the SCM splits `lpBinaryPathName` back into the same
+// arguments we built it from.
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c == ' ' || c == '\t' || c == '"') {
+        return arg.to_string();
+    }
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    let mut chars = arg.chars().peekable();
+    loop {
+        let mut backslashes = 0;
+        while chars.peek() == Some(&'\\') {
+            backslashes += 1;
+            chars.next();
+        }
+        match chars.peek() {
+            Some('"') => {
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                quoted.push('"');
+                chars.next();
+            }
+            Some(_) => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                quoted.push(chars.next().unwrap());
+            }
+            None => {
+                quoted.push_str(&"\\".repeat(backslashes * 2));
+                break;
+            }
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Builds the quoted `lpBinaryPathName`: the service binary's path, followed
+// by any extra arguments, each quoted so the SCM (which re-splits this
+// string with `CommandLineToArgvW`) recovers exactly `binary_path` and
+// `args` as separate arguments.
+fn build_binary_path(binary_path: &str, args: &[&str]) -> String {
+    let mut full = quote_arg(binary_path);
+    for arg in args {
+        full.push(' ');
+        full.push_str(&quote_arg(arg));
+    }
+    full
+}
+
+impl ServiceManager {
+    /// Opens the SCM database on the local computer with full access.
+    pub fn local_computer() -> Result<Self, ManagerError> {
+        let handle = unsafe {
+            winsvc::OpenSCManagerW(null_mut(), null_mut(), winsvc::SC_MANAGER_ALL_ACCESS)
+        };
+        if handle.is_null() {
+            return Err(ManagerError::last());
+        }
+        Ok(ServiceManager { handle })
+    }
+
+    /// Registers a new service with the SCM.
+    pub fn install_service(&self, config: &ServiceConfig) -> Result<(), ManagerError> {
+        let name_wstr = U16CString::from_str(config.name).unwrap();
+        let display_name_wstr = U16CString::from_str(config.display_name).unwrap();
+        let binary_path_wstr =
+            U16CString::from_str(build_binary_path(config.binary_path, config.args)).unwrap();
+        let dependencies_wstr = build_dependencies_wstr(config.dependencies);
+        let (account_wstr, password_wstr) = match &config.account {
+            Some(account) => (
+                Some(U16CString::from_str(account.username).unwrap()),
+                account
+                    .password
+                    .map(|password| U16CString::from_str(password).unwrap()),
+            ),
+            None => (None, None),
+        };
+
+        let service_handle = unsafe {
+            winsvc::CreateServiceW(
+                self.handle,
+                name_wstr.as_ptr(),
+                display_name_wstr.as_ptr(),
+                winsvc::SERVICE_ALL_ACCESS,
+                winsvc::SERVICE_WIN32_OWN_PROCESS,
+                config.start_type.to_win32(),
+                winsvc::SERVICE_ERROR_NORMAL,
+                binary_path_wstr.as_ptr(),
+                null_mut(),
+                null_mut(),
+                dependencies_wstr
+                    .as_ref()
+                    .map_or(null_mut(), |d| d.as_ptr() as *mut u16),
+                account_wstr.as_ref().map_or(null_mut(), |a| a.as_ptr() as *mut u16),
+                password_wstr.as_ref().map_or(null_mut(), |p| p.as_ptr() as *mut u16),
+            )
+        };
+        if service_handle.is_null() {
+            return Err(ManagerError::last());
+        }
+
+        let result = (|| {
+            if let Some(description) = config.description {
+                let mut description_wstr = U16CString::from_str(description).unwrap();
+                let mut service_description = winsvc::SERVICE_DESCRIPTIONW {
+                    lpDescription: description_wstr.as_mut_ptr(),
+                };
+                let ok = unsafe {
+                    winsvc::ChangeServiceConfig2W(
+                        service_handle,
+                        winsvc::SERVICE_CONFIG_DESCRIPTION,
+                        &mut service_description as *mut _ as *mut winapi::ctypes::c_void,
+                    )
+                };
+                if ok == 0 {
+                    return Err(ManagerError::last());
+                }
+            }
+            Ok(())
+        })();
+
+        unsafe {
+            winsvc::CloseServiceHandle(service_handle);
+        }
+        result
+    }
+
+    /// Removes a service from the SCM database. Windows only deletes the
+    /// service once it is stopped and no handles remain open.
+    pub fn delete_service(&self, name: &str) -> Result<(), ManagerError> {
+        let name_wstr = U16CString::from_str(name).unwrap();
+        unsafe {
+            let service_handle =
+                winsvc::OpenServiceW(self.handle, name_wstr.as_ptr(), winsvc::DELETE);
+            if service_handle.is_null() {
+                return Err(ManagerError::last());
+            }
+            let ok = winsvc::DeleteService(service_handle);
+            winsvc::CloseServiceHandle(service_handle);
+            if ok == 0 {
+                return Err(ManagerError::last());
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a service with no extra arguments.
+    pub fn start_service(&self, name: &str) -> Result<(), ManagerError> {
+        let name_wstr = U16CString::from_str(name).unwrap();
+        unsafe {
+            let service_handle =
+                winsvc::OpenServiceW(self.handle, name_wstr.as_ptr(), winsvc::SERVICE_START);
+            if service_handle.is_null() {
+                return Err(ManagerError::last());
+            }
+            let ok = winsvc::StartServiceW(service_handle, 0, null_mut());
+            winsvc::CloseServiceHandle(service_handle);
+            if ok == 0 {
+                return Err(ManagerError::last());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `SERVICE_CONTROL_STOP` and returns the status observed
+    /// immediately after.
+    pub fn stop_service(&self, name: &str) -> Result<ServiceStatusInfo, ManagerError> {
+        let name_wstr = U16CString::from_str(name).unwrap();
+        unsafe {
+            let service_handle = winsvc::OpenServiceW(
+                self.handle,
+                name_wstr.as_ptr(),
+                winsvc::SERVICE_STOP | winsvc::SERVICE_QUERY_STATUS,
+            );
+            if service_handle.is_null() {
+                return Err(ManagerError::last());
+            }
+            let mut status: winsvc::SERVICE_STATUS = core::mem::zeroed();
+            let ok = winsvc::ControlService(
+                service_handle,
+                winsvc::SERVICE_CONTROL_STOP,
+                &mut status,
+            );
+            winsvc::CloseServiceHandle(service_handle);
+            if ok == 0 {
+                return Err(ManagerError::last());
+            }
+            Ok(ServiceStatusInfo {
+                state: status.dwCurrentState,
+                process_id: 0,
+                checkpoint: status.dwCheckPoint,
+                wait_hint: Duration::from_millis(status.dwWaitHint as u64),
+            })
+        }
+    }
+
+    /// Queries the current `SERVICE_STATUS_PROCESS` for a service.
+    pub fn query_service(&self, name: &str) -> Result<ServiceStatusInfo, ManagerError> {
+        let name_wstr = U16CString::from_str(name).unwrap();
+        unsafe {
+            let service_handle = winsvc::OpenServiceW(
+                self.handle,
+                name_wstr.as_ptr(),
+                winsvc::SERVICE_QUERY_STATUS,
+            );
+            if service_handle.is_null() {
+                return Err(ManagerError::last());
+            }
+
+            let mut status: winsvc::SERVICE_STATUS_PROCESS = core::mem::zeroed();
+            let mut bytes_needed: DWORD = 0;
+            let ok = winsvc::QueryServiceStatusEx(
+                service_handle,
+                winsvc::SC_STATUS_PROCESS_INFO,
+                &mut status as *mut _ as *mut u8,
+                core::mem::size_of::<winsvc::SERVICE_STATUS_PROCESS>() as DWORD,
+                &mut bytes_needed,
+            );
+            winsvc::CloseServiceHandle(service_handle);
+            if ok == 0 {
+                return Err(ManagerError::last());
+            }
+
+            Ok(ServiceStatusInfo {
+                state: status.dwCurrentState,
+                process_id: status.dwProcessId,
+                checkpoint: status.dwCheckPoint,
+                wait_hint: Duration::from_millis(status.dwWaitHint as u64),
+            })
+        }
+    }
+}
+
+impl Drop for ServiceManager {
+    fn drop(&mut self) {
+        unsafe {
+            winsvc::CloseServiceHandle(self.handle);
+        }
+    }
+}